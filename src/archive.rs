@@ -0,0 +1,550 @@
+//! --from-archive: uploads what a zip or tar file (gzipped or not) holds as a
+//! folder tree, reading each entry straight out of the archive instead of
+//! extracting it first. An archive can only be read in order, so entries go
+//! up one at a time: small ones from memory, the rest streamed through a
+//! resumable session a chunk at a time.
+
+use crate::{
+    Body, Checksum, Conflict, DriveClient, DriveFile, Failure, Job, RESUMABLE_THRESHOLD, Summary,
+    Throughput, UploadError, UploadOptions, UploadStats, UploadedFile, WalkOptions,
+    byte_cap_reached, check_checksum, check_status, committed_offset, conflict, file_metadata,
+    filter_reason, format_size, guess_mime, hex, is_older_than_since, max_files_reached,
+    upload_checked, upload_fields, uploaded_file, with_goog_hash,
+};
+use flate2::read::GzDecoder;
+use log::{debug, error, info, warn};
+use md5::{Digest, Md5};
+use reqwest::StatusCode;
+use reqwest::blocking::multipart;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{BufReader, Read};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// One entry of the archive.
+struct Entry {
+    /// `/`-separated, with no `..` or leading `/`.
+    path: String,
+    kind: EntryKind,
+    size: u64,
+    /// When the archive records a time.
+    modified: Option<SystemTime>,
+}
+
+#[derive(PartialEq)]
+enum EntryKind {
+    Folder,
+    File,
+    Link,
+    /// Devices, fifos and the like, which have nothing to upload.
+    Other,
+}
+
+/// Uploads every entry of `archive` below `root_id`, with the upload and
+/// walk options of a normal run: entries are filtered, skipped on conflict
+/// and limited as the walk does files. Failed entries are counted in the
+/// summary, as for files.
+pub(crate) fn upload_archive(
+    drive: &DriveClient,
+    opts: &UploadOptions,
+    walk: &WalkOptions,
+    archive: &Path,
+    root_id: &str,
+) -> Result<Summary, UploadError> {
+    let started = Instant::now();
+    let mut upload = ArchiveUpload {
+        drive,
+        opts,
+        walk,
+        archive,
+        root_id: root_id.to_string(),
+        existing: HashMap::new(),
+        announced: HashSet::new(),
+        hidden: HashSet::new(),
+        summary: Summary::default(),
+        rates: Vec::new(),
+    };
+
+    each_entry(archive, |entry, contents| {
+        walk.pause.wait();
+        if walk.shutdown.load(Ordering::SeqCst) {
+            return Ok(false);
+        }
+        upload.entry(entry, contents)
+    })?;
+
+    let mut summary = upload.summary;
+    summary.throughput = Throughput::from_rates(upload.rates);
+    summary.interrupted = walk.shutdown.load(Ordering::SeqCst);
+    summary.byte_cap_reached = walk.byte_budget.as_ref().is_some_and(|b| b.reached.load(Ordering::SeqCst));
+    summary.limited = walk.limit_reached.load(Ordering::SeqCst) && !summary.byte_cap_reached;
+    summary.elapsed_secs = started.elapsed().as_secs_f64();
+    if walk.dry_run {
+        status!("Dry run: {} files, {} bytes would be uploaded", summary.files, summary.total_bytes);
+    }
+    Ok(summary)
+}
+
+/// Calls `visit` with every entry and a reader for its contents, in archive
+/// order, until it returns false. The kind of archive goes by its extension.
+fn each_entry<F>(archive: &Path, mut visit: F) -> Result<(), UploadError>
+where
+    F: FnMut(Entry, &mut dyn Read) -> Result<bool, UploadError>,
+{
+    let name = archive.file_name().unwrap_or_default().to_string_lossy().to_ascii_lowercase();
+    let file = fs::File::open(archive).map_err(|e| format!("cannot open {}: {}", archive.display(), e))?;
+
+    if name.ends_with(".zip") {
+        let mut zip = zip::ZipArchive::new(BufReader::new(file))
+            .map_err(|e| format!("cannot read {}: {}", archive.display(), e))?;
+        for i in 0..zip.len() {
+            let mut file = zip.by_index(i)?;
+            let Some(path) = file.enclosed_name().as_deref().and_then(entry_path) else {
+                warn!("Skip {} in the archive: its path points outside it", String::from_utf8_lossy(file.name_raw()));
+                continue;
+            };
+            let kind = if file.is_dir() {
+                EntryKind::Folder
+            } else if file.is_symlink() {
+                EntryKind::Link
+            } else {
+                EntryKind::File
+            };
+            // Zip keeps a local time without a zone; it is taken as UTC.
+            let modified = file.last_modified().and_then(|t| {
+                let utc = format!(
+                    "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+                    t.year(),
+                    t.month(),
+                    t.day(),
+                    t.hour(),
+                    t.minute(),
+                    t.second()
+                );
+                humantime::parse_rfc3339(&utc).ok()
+            });
+            let entry = Entry { path, kind, size: file.size(), modified };
+            if !visit(entry, &mut file)? {
+                break;
+            }
+        }
+        return Ok(());
+    }
+
+    let reader: Box<dyn Read> = if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        Box::new(GzDecoder::new(BufReader::new(file)))
+    } else if name.ends_with(".tar") {
+        Box::new(BufReader::new(file))
+    } else {
+        return Err(format!("{} is not a .zip, .tar, .tar.gz or .tgz file", archive.display()).into());
+    };
+    let mut tar = tar::Archive::new(reader);
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        let raw = entry.path()?.into_owned();
+        let Some(path) = entry_path(&raw) else {
+            warn!("Skip {} in the archive: its path points outside it", raw.display());
+            continue;
+        };
+        let header = entry.header();
+        let kind = match header.entry_type() {
+            t if t.is_dir() => EntryKind::Folder,
+            t if t.is_symlink() || t.is_hard_link() => EntryKind::Link,
+            t if t.is_file() || t.is_contiguous() => EntryKind::File,
+            _ => EntryKind::Other,
+        };
+        let modified = header.mtime().ok().map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+        let entry_info = Entry { path, kind, size: entry.size(), modified };
+        if !visit(entry_info, &mut entry)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// An entry's path as `/`-separated names, or None when it is absolute or
+/// climbs out with `..`, as a crafted archive's might.
+fn entry_path(path: &Path) -> Option<String> {
+    let mut names = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => names.push(name.to_string_lossy()),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    Some(names.join("/"))
+}
+
+struct ArchiveUpload<'a> {
+    drive: &'a DriveClient,
+    opts: &'a UploadOptions,
+    walk: &'a WalkOptions,
+    archive: &'a Path,
+    root_id: String,
+    /// What each Drive folder held already, listed the first time a file
+    /// goes into it.
+    existing: HashMap<String, HashMap<String, DriveFile>>,
+    /// Folders a dry run already said it would create.
+    announced: HashSet<String>,
+    /// Hidden entries already counted, so a hidden folder counts once as it
+    /// does in the walk.
+    hidden: HashSet<String>,
+    summary: Summary,
+    rates: Vec<f64>,
+}
+
+impl ArchiveUpload<'_> {
+    /// Takes one entry, returning false once --max-files or
+    /// --max-total-bytes is reached.
+    fn entry(&mut self, entry: Entry, contents: &mut dyn Read) -> Result<bool, UploadError> {
+        let shown = self.archive.join(&entry.path);
+        if self.left_out(&entry.path) {
+            return Ok(true);
+        }
+        match entry.kind {
+            EntryKind::Folder => {
+                if !entry.path.is_empty() {
+                    self.folder_or_fail(&entry.path, &shown);
+                }
+                Ok(true)
+            }
+            EntryKind::Link => {
+                warn!("Skip {}: a link in the archive", shown.display());
+                self.summary.skipped += 1;
+                Ok(true)
+            }
+            EntryKind::Other => {
+                debug!("Skip {}: not a file or folder", shown.display());
+                self.summary.skipped += 1;
+                Ok(true)
+            }
+            EntryKind::File => self.file(entry, contents, shown),
+        }
+    }
+
+    /// Whether --exclude or --include-hidden leave out `path`, or a folder
+    /// above it, as the walk would by not going into that folder. Patterns
+    /// match paths inside the archive.
+    fn left_out(&mut self, path: &str) -> bool {
+        let mut walked = String::new();
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            if !walked.is_empty() {
+                walked.push('/');
+            }
+            walked.push_str(name);
+            if self.walk.exclude.is_match(&walked) {
+                debug!("Excluded {}", self.archive.join(&walked).display());
+                return true;
+            }
+            if !self.walk.include_hidden && name.starts_with('.') {
+                if self.hidden.insert(walked.clone()) {
+                    debug!("Skip hidden {}", self.archive.join(&walked).display());
+                    self.summary.hidden += 1;
+                }
+                return true;
+            }
+        }
+        false
+    }
+
+    /// The Drive folder for `path` inside the archive, created along with any
+    /// missing folders above it. Archives need not list folders before
+    /// the files in them, or at all.
+    fn folder(&mut self, path: &str) -> Result<String, UploadError> {
+        let mut parent = self.root_id.clone();
+        let mut walked = String::new();
+        for name in path.split('/') {
+            if !walked.is_empty() {
+                walked.push('/');
+            }
+            walked.push_str(name);
+            if self.walk.dry_run {
+                if self.announced.insert(walked.clone()) {
+                    status!("Would create folder {}", self.archive.join(&walked).display());
+                }
+                continue;
+            }
+            parent = self.drive.create_folder(self.walk.all_drives, &self.walk.folders, name, Some(&parent))?;
+        }
+        Ok(parent)
+    }
+
+    /// folder, counting the folder as failed rather than stopping the run.
+    fn folder_or_fail(&mut self, path: &str, shown: &Path) -> Option<String> {
+        match self.folder(path) {
+            Ok(id) => Some(id),
+            Err(e) => {
+                error!("Failed to create folder {}: {}", shown.display(), e);
+                self.summary.failed_folders += 1;
+                self.summary.failures.push(Failure {
+                    path: shown.to_string_lossy().into_owned(),
+                    error: e.to_string(),
+                });
+                None
+            }
+        }
+    }
+
+    fn file(&mut self, entry: Entry, contents: &mut dyn Read, shown: PathBuf) -> Result<bool, UploadError> {
+        let (folder, base) = match entry.path.rsplit_once('/') {
+            Some((folder, base)) => (Some(folder), base),
+            None => (None, entry.path.as_str()),
+        };
+        let name = format!("{}{}", self.walk.prefix, base);
+
+        if is_older_than_since(self.walk, entry.modified) {
+            debug!("Skip file {}: not modified since --since", shown.display());
+            self.summary.not_modified += 1;
+            return Ok(true);
+        }
+        if let Some(reason) = filter_reason(self.walk, Path::new(&entry.path), entry.size) {
+            debug!("Skip file {}: {}", shown.display(), reason);
+            self.summary.filtered += 1;
+            return Ok(true);
+        }
+        if let Some(limit) = self.opts.max_file_size
+            && entry.size > limit
+        {
+            warn!("Skip file {}: larger than {}", shown.display(), format_size(limit));
+            self.summary.skipped += 1;
+            return Ok(true);
+        }
+
+        if self.walk.dry_run {
+            if !self.take(entry.size) {
+                return Ok(false);
+            }
+            if let Some(folder) = folder {
+                self.folder(folder)?;
+            }
+            status!("Would upload {} ({} bytes)", shown.display(), entry.size);
+            return Ok(true);
+        }
+
+        let parent_id = match folder {
+            Some(folder) => match self.folder_or_fail(folder, &shown) {
+                Some(id) => id,
+                None => return Ok(true),
+            },
+            None => self.root_id.clone(),
+        };
+
+        let existing = match self.existing.get(&parent_id) {
+            Some(existing) => existing,
+            None => {
+                let listed = self.drive.list_files(self.walk.all_drives, &parent_id)?;
+                self.existing.entry(parent_id.clone()).or_insert(listed)
+            }
+        };
+        let on_drive = existing.get(&name).filter(|f| !f.folder);
+        let replace_id = match on_drive.map(|f| conflict(self.walk, f, entry.size)) {
+            Some(Conflict::Skip) => {
+                warn!("Skip file {}: already in Drive", shown.display());
+                self.summary.skipped += 1;
+                return Ok(true);
+            }
+            Some(Conflict::Replace(id)) => Some(id),
+            Some(Conflict::Upload) | None => None,
+        };
+        if !self.take(entry.size) {
+            return Ok(false);
+        }
+
+        let job = Job {
+            path: shown.clone(),
+            original: self.archive.to_path_buf(),
+            parent_id,
+            name,
+            gzip: false,
+            previous: None,
+            source_path: entry.path.clone(),
+            replace_id,
+            encryption: None,
+        };
+        match upload_entry(self.drive, self.opts, &job, &entry, contents) {
+            Ok(stats) => {
+                if let Some(budget) = &self.opts.byte_budget {
+                    budget.settle(entry.size, stats.bytes_sent);
+                }
+                info!("Uploaded {} ({})", shown.display(), format_size(entry.size));
+                self.rates.push(stats.mb_per_sec());
+                self.summary.uploaded += 1;
+            }
+            Err(e) => {
+                error!("Failed to upload {}: {}", shown.display(), e);
+                self.summary.failed += 1;
+                self.summary.failures.push(Failure {
+                    path: shown.to_string_lossy().into_owned(),
+                    error: e.to_string(),
+                });
+                self.summary.total_bytes -= entry.size;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Counts a file of `size` bytes to upload, or stops at --max-files or
+    /// --max-total-bytes as the walk does.
+    fn take(&mut self, size: u64) -> bool {
+        if max_files_reached(self.walk, self.summary.files) || byte_cap_reached(self.walk, size) {
+            return false;
+        }
+        self.summary.files += 1;
+        self.summary.total_bytes += size;
+        true
+    }
+}
+
+/// Uploads one file entry, in one request when it is small enough to hold
+/// in memory and chunk by chunk otherwise.
+fn upload_entry(
+    drive: &DriveClient,
+    opts: &UploadOptions,
+    job: &Job,
+    entry: &Entry,
+    contents: &mut dyn Read,
+) -> Result<UploadStats, UploadError> {
+    let started = Instant::now();
+    let mime_type = guess_mime(opts, Path::new(&job.name));
+    let mut metadata = file_metadata(opts, job, &mime_type)?;
+    // The times file_metadata found are the archive's own.
+    let fields = metadata.as_object_mut().unwrap();
+    fields.remove("createdTime");
+    match entry.modified {
+        Some(modified) => {
+            let modified = humantime::format_rfc3339_seconds(modified).to_string();
+            fields.insert("modifiedTime".into(), modified.into())
+        }
+        None => fields.remove("modifiedTime"),
+    };
+
+    // An empty multipart body is not reliably accepted.
+    if entry.size == 0 {
+        let uploaded = drive.create_empty_file(opts, job)?;
+        return Ok(UploadStats::new(uploaded, 0, started));
+    }
+    if entry.size > RESUMABLE_THRESHOLD {
+        let uploaded = upload_streamed(drive, opts, job, &metadata, &mime_type, entry.size, contents)?;
+        return Ok(UploadStats::new(uploaded, entry.size, started));
+    }
+
+    let mut data = Vec::with_capacity(entry.size as usize);
+    contents.read_to_end(&mut data)?;
+    let md5 = hex(&Md5::digest(&data));
+
+    // Unlike a file, the entry can't be read again, but it is all in memory.
+    let body = Body::Memory { data: &data, md5: &md5 };
+    upload_checked(drive, opts, job, body, started, |_| {
+        if let Some(limiter) = &opts.rate_limit {
+            limiter.acquire(data.len());
+        }
+        drive.send_upload(opts, job, metadata.clone(), |metadata| {
+            let meta_part = multipart::Part::text(metadata.to_string()).mime_str("application/json")?;
+            let file_part = multipart::Part::bytes(data.clone())
+                .file_name(job.name.clone())
+                .mime_str(&mime_type)?;
+            let form = multipart::Form::new().part("metadata", meta_part).part("file", file_part);
+            let req = drive
+                .upload_request(job)
+                .query(&[("uploadType", "multipart"), ("fields", upload_fields(opts))])
+                .multipart(form);
+            Ok(with_goog_hash(req, Some(&md5)))
+        })
+    })
+}
+
+/// A resumable upload fed from a reader that can only go forward. What a
+/// chunk's 308 leaves uncommitted is kept and sent at the start of the next
+/// one; the MD5 is worked out as the bytes are read, in time for the
+/// X-Goog-Hash on the last chunk.
+fn upload_streamed(
+    drive: &DriveClient,
+    opts: &UploadOptions,
+    job: &Job,
+    metadata: &serde_json::Value,
+    mime_type: &str,
+    total: u64,
+    contents: &mut dyn Read,
+) -> Result<UploadedFile, UploadError> {
+    let resp = drive.send(opts.all_drives, || {
+        Ok(drive
+            .upload_request(job)
+            .query(&[("uploadType", "resumable"), ("fields", upload_fields(opts))])
+            .header("X-Upload-Content-Type", mime_type)
+            .header("X-Upload-Content-Length", total)
+            .json(metadata))
+    })?;
+    let session_uri = check_status(resp)?
+        .headers()
+        .get("Location")
+        .and_then(|v| v.to_str().ok())
+        .ok_or("Resumable session created but no Location header")?
+        .to_string();
+
+    let mut hasher = Md5::new();
+    let mut md5 = None;
+    // Read from the archive but not yet committed by Drive.
+    let mut pending: Vec<u8> = Vec::new();
+    let mut read = 0u64;
+    let mut offset = 0u64;
+
+    loop {
+        opts.pause.wait();
+        let size = opts.chunks.get();
+        while pending.len() < size && read < total {
+            let mut buf = vec![0u8; (size - pending.len()).min((total - read) as usize)];
+            let n = contents.read(&mut buf)?;
+            if n == 0 {
+                return Err(format!("archive entry ended after {} of {} bytes", read, total).into());
+            }
+            hasher.update(&buf[..n]);
+            pending.extend_from_slice(&buf[..n]);
+            read += n as u64;
+        }
+        if read == total && md5.is_none() {
+            md5 = Some(hex(&std::mem::take(&mut hasher).finalize()));
+        }
+        let len = pending.len();
+
+        if let Some(limiter) = &opts.rate_limit {
+            limiter.acquire(len);
+        }
+        let range = format!("bytes {}-{}/{}", offset, offset + len as u64 - 1, total);
+        let last = offset + len as u64 == total;
+        let sent = Instant::now();
+        let resp = drive.send(false, || {
+            let req = drive
+                .http
+                .put(&session_uri)
+                .header("Content-Range", range.as_str())
+                .body(pending.clone());
+            Ok(if last { with_goog_hash(req, md5.as_deref()) } else { req })
+        })?;
+
+        if resp.status() == StatusCode::PERMANENT_REDIRECT {
+            opts.chunks.record(len, sent.elapsed());
+            let committed = committed_offset(&resp)?;
+            if committed < offset || committed > offset + len as u64 {
+                return Err(format!("Drive committed {} of a chunk ending at {}", committed, offset + len as u64).into());
+            }
+            pending.drain(..(committed - offset) as usize);
+            offset = committed;
+            continue;
+        }
+
+        let uploaded = uploaded_file(check_status(resp)?)?;
+        // The entry has been read, so there is no going again; the damaged
+        // copy is removed rather than left looking uploaded.
+        let local_md5 = md5.as_deref().filter(|_| opts.verify);
+        if let Checksum::Mismatch { damaged } = check_checksum(job, local_md5, &uploaded) {
+            if let Some(id) = damaged {
+                drive.delete_file(opts.all_drives, &id)?;
+            }
+            return Err("checksum mismatch".into());
+        }
+        return Ok(uploaded);
+    }
+}
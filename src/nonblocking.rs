@@ -0,0 +1,366 @@
+//! The --async upload pool. Small files go up as tasks on a tokio runtime, at
+//! most --threads at a time, so a request waiting on the network holds no OS
+//! thread. Everything else (empty and resumable-sized files, --compress,
+//! --encrypt and --dedup-content) runs the blocking upload_file on tokio's
+//! blocking pool, which keeps one code path for the rarer cases.
+
+use crate::{
+    Attempt, CHECKSUM_RETRIES_EXHAUSTED, Checksum, Cli, Create, Job, RESUMABLE_THRESHOLD, RetryPolicy,
+    UploadError, UploadOptions, UploadStats, UploadedFile, WorkerContext, all_drives_query,
+    check_checksum, classify_drive_error, created_file, file_metadata, goog_hash, guess_mime, hex, parse_proxy,
+    status_error, upload_fields, upload_file, CONNECT_TIMEOUT, REQUEST_TIMEOUT,
+};
+use log::{error, warn};
+use md5::{Digest, Md5};
+use reqwest::multipart::{Form, Part};
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use std::fs;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+use tokio::sync::{Semaphore, mpsc};
+use tokio::task::JoinSet;
+
+/// Builds the runtime and the async client up front, so a bad setting fails
+/// the run before anything is uploaded, then drives the pool from a thread
+/// of its own. The thread ends once `jobs` is closed and drained.
+pub(crate) fn spawn_pool(
+    ctx: WorkerContext,
+    jobs: Arc<Mutex<Receiver<Job>>>,
+    cli: &Cli,
+    limit: usize,
+) -> Result<thread::JoinHandle<()>, UploadError> {
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build()?;
+
+    let mut builder = Client::builder()
+        .connect_timeout(cli.connect_timeout.unwrap_or(CONNECT_TIMEOUT))
+        .pool_max_idle_per_host(limit)
+        .gzip(cli.http_gzip);
+    let timeout = cli.timeout.unwrap_or(REQUEST_TIMEOUT);
+    if !timeout.is_zero() {
+        builder = builder.timeout(timeout);
+    }
+    if let Some(proxy) = parse_proxy(cli.proxy.as_deref())? {
+        builder = builder.proxy(proxy);
+    }
+    let client = builder.build()?;
+
+    Ok(thread::spawn(move || runtime.block_on(run_pool(ctx, client, jobs, limit))))
+}
+
+async fn run_pool(ctx: WorkerContext, client: Client, jobs: Arc<Mutex<Receiver<Job>>>, limit: usize) {
+    // The walk feeds a std channel; this one holds back no more jobs than
+    // can start, so the walk still feels backpressure.
+    let (tx, mut rx) = mpsc::channel::<Job>(limit);
+    let forward = tokio::task::spawn_blocking(move || {
+        loop {
+            let job = { jobs.lock().unwrap().recv() };
+            let Ok(job) = job else { break };
+            if tx.blocking_send(job).is_err() {
+                break;
+            }
+        }
+    });
+
+    let permits = Arc::new(Semaphore::new(limit));
+    let mut tasks = JoinSet::new();
+    let mut panicked = 0;
+
+    while let Some(job) = rx.recv().await {
+        let permit = Arc::clone(&permits)
+            .acquire_owned()
+            .await
+            .expect("the semaphore is never closed");
+        let ctx = ctx.clone();
+        let client = client.clone();
+        tasks.spawn(async move {
+            let _permit = permit;
+            let progress = Arc::clone(&ctx.progress);
+            upload_job(ctx, client, job).await;
+            progress.handled.fetch_add(1, Ordering::Relaxed);
+        });
+
+        // Reap as we go so the set stays around `limit` tasks.
+        while let Some(result) = tasks.try_join_next() {
+            panicked += result.is_err() as usize;
+        }
+    }
+
+    let _ = forward.await;
+    while let Some(result) = tasks.join_next().await {
+        panicked += result.is_err() as usize;
+    }
+
+    // Counted like a panicked worker thread when the pool is joined.
+    if panicked > 0 {
+        error!("{} upload task(s) panicked", panicked);
+        panic!("{} upload task(s) panicked", panicked);
+    }
+}
+
+/// Runs `f` on the blocking pool, passing a panic on to the calling task.
+async fn blocking<T, F>(f: F) -> T
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(value) => value,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+async fn upload_job(ctx: WorkerContext, client: Client, job: Job) {
+    // Waiting out a pause, hashing for --verify checksum and a
+    // --pre-upload-cmd hook all block.
+    let prepared = blocking({
+        let ctx = ctx.clone();
+        move || {
+            ctx.upload_opts.pause.wait();
+            let started = Instant::now();
+            ctx.prepare(&job, started).map(|stamp| (job, stamp, started))
+        }
+    })
+    .await;
+    let Some((job, stamp, started)) = prepared else {
+        return;
+    };
+
+    let path = job.path.clone();
+    let result = if takes_async_path(&ctx.upload_opts, stamp.size) {
+        upload_small(&ctx, &client, &job, started).await
+    } else {
+        let ctx = ctx.clone();
+        blocking(move || {
+            upload_file(&ctx.drive, &ctx.upload_opts, &job)
+        })
+        .await
+    };
+    // A --post-upload-cmd hook is waited for.
+    blocking(move || ctx.finish(path, stamp, result, started)).await;
+}
+
+fn takes_async_path(opts: &UploadOptions, size: u64) -> bool {
+    size > 0
+        && size <= RESUMABLE_THRESHOLD
+        && !opts.compress
+        && opts.passphrase.is_none()
+        && opts.dedup.is_none()
+}
+
+/// DriveClient::upload_multipart and the checksum check of upload_contents,
+/// with the file read into memory first; it is at most RESUMABLE_THRESHOLD
+/// bytes.
+async fn upload_small(
+    ctx: &WorkerContext,
+    client: &Client,
+    job: &Job,
+    started: Instant,
+) -> Result<UploadStats, UploadError> {
+    let opts = &ctx.upload_opts;
+    let path = job.path.clone();
+    let data = blocking(move || fs::read(path)).await?;
+    let size = data.len() as u64;
+
+    // The walk already filtered on size, but the file may have grown since.
+    if let Some(limit) = opts.max_file_size
+        && size > limit
+    {
+        return Err(UploadError::FileTooLarge);
+    }
+
+    let mime_type = guess_mime(opts, &job.path);
+    let metadata = file_metadata(opts, job, &mime_type)?;
+    let local_md5 = opts.verify.then(|| hex(&Md5::digest(&data)));
+    let mut bytes_sent = 0;
+
+    for attempt in 0..2 {
+        if let Some(limiter) = &opts.rate_limit {
+            tokio::time::sleep(limiter.reserve(data.len())).await;
+        }
+        bytes_sent += size;
+
+        let result = send_upload(ctx, job, &metadata, |tk, metadata| {
+            let meta_part = Part::text(metadata.to_string()).mime_str("application/json")?;
+            let file_part = Part::bytes(data.clone())
+                .file_name(metadata["name"].as_str().unwrap_or_default().to_string())
+                .mime_str(&mime_type)?;
+            let form = Form::new().part("metadata", meta_part).part("file", file_part);
+
+            let req = match &job.replace_id {
+                Some(id) => client.patch(ctx.drive.endpoints.upload_file(id)),
+                None => client.post(ctx.drive.endpoints.upload_files()),
+            };
+            let mut req = req
+                .query(&[("uploadType", "multipart"), ("fields", upload_fields(opts))])
+                .query(all_drives_query(opts.all_drives))
+                .bearer_auth(tk)
+                .multipart(form);
+            if let Some(hash) = local_md5.as_deref().and_then(goog_hash) {
+                req = req.header("X-Goog-Hash", hash);
+            }
+            Ok(req)
+        })
+        .await;
+        let uploaded = match result {
+            // The data is in memory, so it was damaged on the way.
+            Err(UploadError::HashMismatch(msg)) if attempt == 0 => {
+                warn!("Drive refused {} as corrupted ({}), re-uploading", job.path.display(), msg);
+                continue;
+            }
+            result => result?,
+        };
+
+        match check_checksum(job, local_md5.as_deref(), &uploaded) {
+            Checksum::Matches => return Ok(UploadStats::verified(uploaded, bytes_sent, started)),
+            Checksum::Unchecked => return Ok(UploadStats::new(uploaded, bytes_sent, started)),
+            Checksum::Mismatch { damaged } => {
+                if let Some(id) = damaged {
+                    let url = ctx.drive.endpoints.file(&id);
+                    let resp = send_authorized(ctx, &ctx.drive.retry, |tk| {
+                        Ok(client.delete(&url).query(all_drives_query(opts.all_drives)).bearer_auth(tk))
+                    })
+                    .await?;
+                    check_status(resp).await?;
+                }
+                if attempt == 0 {
+                    warn!("Re-uploading {}", job.path.display());
+                }
+            }
+        }
+    }
+
+    Err(CHECKSUM_RETRIES_EXHAUSTED.into())
+}
+
+/// DriveClient::send_upload for the async client; the search for a create
+/// that may have gone through runs on the blocking pool.
+async fn send_upload<F>(
+    ctx: &WorkerContext,
+    job: &Job,
+    metadata: &serde_json::Value,
+    build: F,
+) -> Result<UploadedFile, UploadError>
+where
+    F: Fn(&str, &serde_json::Value) -> Result<RequestBuilder, UploadError>,
+{
+    if job.replace_id.is_some() {
+        let resp = send_authorized(ctx, &ctx.drive.retry, |tk| build(tk, metadata)).await?;
+        return uploaded_file(check_status(resp).await?).await;
+    }
+
+    let mut metadata = metadata.clone();
+    let create = Create::new(&ctx.drive.retry, &mut metadata);
+    let mut attempt = 0;
+
+    loop {
+        let result = send_authorized(ctx, &create.policy, |tk| build(tk, &metadata)).await;
+        let outcome = match &result {
+            Ok(resp) => Attempt::Response(resp.status(), resp.headers()),
+            Err(UploadError::Request(e)) => Attempt::Failed(e),
+            Err(_) => Attempt::Refused,
+        };
+        let Some(delay) = create.next_delay(attempt, outcome) else {
+            return uploaded_file(check_status(result?).await?).await;
+        };
+        let found = blocking({
+            let ctx = ctx.clone();
+            let query = create.query.clone();
+            move || {
+                let fields = upload_fields(&ctx.upload_opts);
+                ctx.drive.list_children(ctx.upload_opts.all_drives, &query, fields)
+            }
+        })
+        .await?
+        .pop();
+        if let Some(found) = found {
+            warn!("Create request failed but Drive made {} anyway", metadata["name"]);
+            return created_file(found);
+        }
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+async fn uploaded_file(resp: Response) -> Result<UploadedFile, UploadError> {
+    let body = resp.text().await?;
+    let file = serde_json::from_str(&body)
+        .map_err(|e| format!("File uploaded but response unreadable ({}): {}", e, body))?;
+    Ok(file)
+}
+
+/// DriveClient::send for the async client. The token lock is only held to
+/// copy the token out, never across a request, so taking it here doesn't
+/// stall the runtime; the refresh itself is a blocking call and goes to the
+/// blocking pool, where it may wait on another thread's refresh.
+async fn send_authorized<F>(ctx: &WorkerContext, policy: &RetryPolicy, build: F) -> Result<Response, UploadError>
+where
+    F: Fn(&str) -> Result<RequestBuilder, UploadError>,
+{
+    let tk = { ctx.drive.token.lock().unwrap().clone() };
+    let resp = send_with_retry(policy, || build(&tk.value)).await?;
+
+    if resp.status() != StatusCode::UNAUTHORIZED {
+        return Ok(resp);
+    }
+
+    let tk = blocking({
+        let ctx = ctx.clone();
+        move || ctx.drive.refresh_token(tk.generation)
+    })
+    .await?;
+    send_with_retry(policy, || build(&tk)).await
+}
+
+/// send_with_retry for the async client, sleeping on the runtime's timer
+/// instead of the thread.
+async fn send_with_retry<F>(policy: &RetryPolicy, build: F) -> Result<Response, UploadError>
+where
+    F: Fn() -> Result<RequestBuilder, UploadError>,
+{
+    let mut attempt = 0;
+
+    loop {
+        let req = build()?;
+        // Only an open breaker is waited on the blocking pool.
+        let probe = match &policy.breaker {
+            Some(breaker) => match breaker.try_enter() {
+                Some(probe) => probe,
+                None => {
+                    let breaker = Arc::clone(breaker);
+                    blocking(move || breaker.enter()).await
+                }
+            },
+            None => false,
+        };
+        if let Some(limiter) = &policy.requests {
+            tokio::time::sleep(limiter.reserve(1)).await;
+        }
+        let result = req.send().await;
+        let outcome = match &result {
+            Ok(resp) => Attempt::Response(resp.status(), resp.headers()),
+            Err(e) => Attempt::Failed(e),
+        };
+        match policy.next_delay(probe, attempt, outcome) {
+            Some(delay) => tokio::time::sleep(delay).await,
+            None => return Ok(result?),
+        }
+        attempt += 1;
+    }
+}
+
+/// check_status for the async client's responses.
+async fn check_status(resp: Response) -> Result<Response, UploadError> {
+    let status = resp.status();
+
+    if status.is_success() {
+        return Ok(resp);
+    }
+    if let Some(e) = status_error(status, resp.headers()) {
+        return Err(e);
+    }
+    let body = resp.text().await?;
+    Err(classify_drive_error(status, body))
+}
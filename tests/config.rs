@@ -0,0 +1,75 @@
+//! --config files, read through `run` before anything talks to Drive.
+
+use clap::Parser;
+use drive_uploader::{Cli, Command, OnConflict, run};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Writes `contents` as a config file for one test below Cargo's scratch
+/// directory.
+fn config_file(name: &str, contents: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("config.toml");
+    fs::write(&path, contents).unwrap();
+    path
+}
+
+fn run_with(config: &Path) -> String {
+    let cli = Cli::parse_from(["drive-uploader", "--config", config.to_str().unwrap()]);
+    run(cli).unwrap_err().to_string()
+}
+
+#[test]
+fn a_misspelt_key_is_refused() {
+    let path = config_file("config-unknown", "on-conflit = \"skip\"\n");
+    let err = run_with(&path);
+    assert!(err.contains("invalid config"), "{}", err);
+    assert!(err.contains("on-conflit"), "{}", err);
+}
+
+#[test]
+fn a_value_the_flag_would_refuse_is_refused() {
+    let path = config_file("config-bad-value", "on-conflict = \"sometimes\"\n");
+    let err = run_with(&path);
+    assert!(err.contains("invalid config"), "{}", err);
+    assert!(err.contains("sometimes"), "{}", err);
+}
+
+#[test]
+fn every_option_has_a_key_and_the_command_line_wins() {
+    let path = config_file(
+        "config-all",
+        "on-conflict = \"replace\"\nmax-retries = 9\nforce = true\nverbose = 2\nsince = 2024-05-01\n\
+         max-file-size = \"2G\"\nexclude = [\"*.tmp\"]\nparent = \"folder-1\"\nkey-file = \"key\"\n\n\
+         [mime]\nmd = \"text/markdown\"\n",
+    );
+    let mut cli = Cli::parse_from([
+        "drive-uploader", "--config", path.to_str().unwrap(),
+        // The default, given on purpose, still wins over the file.
+        "--on-conflict", "skip", "--max-retries", "2", "--exclude", "*.log",
+        "--shared-drive", "drive-1", "--encrypt", "verify",
+    ]);
+    cli.apply_config().unwrap();
+    assert!(cli.on_conflict == OnConflict::Skip);
+    assert_eq!(cli.max_retries, 2);
+    assert!(cli.force);
+    assert_eq!(cli.verbose, 2);
+    assert!(cli.since.is_some());
+    assert_eq!(cli.max_file_size, Some(2_000_000_000));
+    assert_eq!(cli.exclude, ["*.tmp", "*.log"]);
+    assert_eq!(cli.mime, [("md".to_string(), "text/markdown".to_string())]);
+    // A key that conflicts with a flag gives way; one needing a flag gets it.
+    assert_eq!((cli.parent.as_deref(), cli.shared_drive.as_deref()), (None, Some("drive-1")));
+    assert_eq!(cli.key_file, Some(path.parent().unwrap().join("key")));
+    assert!(matches!(cli.command, Some(Command::Verify)));
+}
+
+#[test]
+fn a_supported_key_is_applied() {
+    let path = config_file("config-source", "source = \"missing-folder\"\n");
+    let err = run_with(&path);
+    // Relative to the config file, and checked before any credentials are read.
+    let expected = path.parent().unwrap().join("missing-folder");
+    assert!(err.contains(&expected.display().to_string()), "{}", err);
+}
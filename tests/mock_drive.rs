@@ -0,0 +1,1238 @@
+//! The uploader against a mock Drive API: wiremock serves the token, files
+//! and upload endpoints on localhost, and each test checks what reached it.
+
+use clap::Parser;
+use drive_uploader::{Cli, DriveEndpoints, OAuthConfig, OnConflict, UploadError, Uploader, UploaderConfig};
+use md5::{Digest, Md5};
+use serde_json::json;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use wiremock::matchers::{
+    body_partial_json, body_string_contains, header, method, path, query_param, query_param_contains,
+    query_param_is_missing,
+};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+const FOLDER_MIME: &str = "application/vnd.google-apps.folder";
+
+/// A mock server kept running on its own runtime, so the blocking client can
+/// be used from the test thread.
+struct MockDrive {
+    rt: Runtime,
+    server: MockServer,
+}
+
+impl MockDrive {
+    fn start() -> Self {
+        let rt = Runtime::new().unwrap();
+        let server = rt.block_on(MockServer::start());
+        MockDrive { rt, server }
+    }
+
+    fn mount(&self, mock: Mock) {
+        self.rt.block_on(mock.mount(&self.server));
+    }
+
+    /// Answers the token endpoint with `token` for every refresh.
+    fn token(&self, token: &str) {
+        self.mount(
+            Mock::given(method("POST"))
+                .and(path("/token"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                    "access_token": token,
+                    "expires_in": 3600,
+                }))),
+        );
+    }
+
+    /// Lists every Drive folder as empty.
+    fn empty_folders(&self) {
+        self.mount(
+            Mock::given(method("GET"))
+                .and(path("/drive/v3/files"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "files": [] }))),
+        );
+        self.mount(
+            Mock::given(method("GET"))
+                .and(path("/drive/v3/about"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "storageQuota": {} }))),
+        );
+    }
+
+    fn config(&self) -> UploaderConfig {
+        self.config_from(&[])
+    }
+
+    /// The test settings on top of these command-line flags.
+    fn config_from(&self, args: &[&str]) -> UploaderConfig {
+        let oauth = OAuthConfig::from_refresh_token("client-id", "client-secret", "refresh-token");
+        let cli = Cli::parse_from(["drive-uploader"].iter().chain(args));
+        UploaderConfig::from(cli)
+            .oauth(oauth)
+            .endpoints(DriveEndpoints::at(&self.server.uri()))
+            .requests_per_100s(0)
+            .retry_delay(Duration::from_millis(10))
+            .quiet(true)
+    }
+
+    /// Has the run's Drive root folder exist as `root-id`, holding `files`.
+    fn root_folder_with(&self, files: serde_json::Value) {
+        self.mount(
+            Mock::given(method("GET"))
+                .and(path("/drive/v3/files"))
+                .and(query_param_contains("q", "'root' in parents"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "files": [{ "id": "root-id" }] })))
+                .with_priority(1),
+        );
+        self.mount(
+            Mock::given(method("GET"))
+                .and(path("/drive/v3/files"))
+                .and(query_param_contains("q", "'root-id' in parents"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "files": files })))
+                .with_priority(1),
+        );
+        self.empty_folders();
+    }
+
+    /// Checks every mock's `expect` count.
+    fn verify(&self) {
+        self.rt.block_on(self.server.verify());
+    }
+}
+
+/// An empty folder for one test below Cargo's scratch directory.
+fn scratch(name: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn md5_hex(data: &[u8]) -> String {
+    Md5::digest(data).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// A successful upload's response for `data`.
+fn uploaded(id: &str, data: &[u8]) -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_json(json!({ "id": id, "md5Checksum": md5_hex(data) }))
+}
+
+#[test]
+fn fetches_a_token_from_the_refresh_token() {
+    let drive = MockDrive::start();
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .and(body_string_contains("grant_type=refresh_token"))
+            .and(body_string_contains("refresh_token=refresh-token"))
+            .and(body_string_contains("client_id=client-id"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "access_token": "tok-1" })))
+            .expect(1),
+    );
+    let data = b"hello";
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/upload/drive/v3/files"))
+            .and(header("authorization", "Bearer tok-1"))
+            .respond_with(uploaded("file-1", data))
+            .expect(1),
+    );
+
+    let dir = scratch("token");
+    fs::write(dir.join("a.txt"), data).unwrap();
+    let uploader = Uploader::new(drive.config()).unwrap();
+    uploader.upload_file(&dir.join("a.txt"), "parent-id").unwrap();
+    drive.verify();
+}
+
+#[test]
+fn a_revoked_refresh_token_fails_construction() {
+    let drive = MockDrive::start();
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .respond_with(ResponseTemplate::new(400).set_body_json(json!({ "error": "invalid_grant" }))),
+    );
+
+    let err = Uploader::new(drive.config()).err().expect("construction should fail");
+    // Typed, for callers to match on, and still readable when printed.
+    assert!(matches!(err, UploadError::TokenRevoked), "{}", err);
+    assert!(err.to_string().contains("revoked"), "{}", err);
+}
+
+#[test]
+fn creates_the_folder_tree_once() {
+    let drive = MockDrive::start();
+    drive.token("tok");
+    drive.empty_folders();
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/drive/v3/files"))
+            .and(body_partial_json(json!({ "name": "ImportantFiles", "mimeType": FOLDER_MIME })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "id": "root-id" })))
+            .expect(1),
+    );
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/drive/v3/files"))
+            .and(body_partial_json(json!({ "name": "sub", "mimeType": FOLDER_MIME, "parents": ["root-id"] })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "id": "sub-id" })))
+            .expect(1),
+    );
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/upload/drive/v3/files"))
+            .and(body_string_contains(r#""parents":["sub-id"]"#))
+            .respond_with(uploaded("file-b", b"two"))
+            .expect(1),
+    );
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/upload/drive/v3/files"))
+            .and(body_string_contains(r#""parents":["root-id"]"#))
+            .respond_with(uploaded("file-a", b"one"))
+            .expect(1),
+    );
+
+    let dir = scratch("folders");
+    fs::create_dir(dir.join("sub")).unwrap();
+    fs::write(dir.join("a.txt"), b"one").unwrap();
+    fs::write(dir.join("sub/b.txt"), b"two").unwrap();
+    let summary = Uploader::new(drive.config().threads(2)).unwrap().upload_dir(&dir).unwrap();
+    assert_eq!((summary.uploaded, summary.failed, summary.failed_folders), (2, 0, 0));
+    drive.verify();
+}
+
+#[test]
+fn uploads_a_small_file_in_one_multipart_request() {
+    let drive = MockDrive::start();
+    drive.token("tok");
+    let data = b"small file contents";
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/upload/drive/v3/files"))
+            .and(query_param("uploadType", "multipart"))
+            .and(body_string_contains(r#""name":"small.txt""#))
+            .and(body_string_contains("small file contents"))
+            .respond_with(uploaded("file-1", data))
+            .expect(1),
+    );
+
+    let dir = scratch("multipart");
+    fs::write(dir.join("small.txt"), data).unwrap();
+    let file = Uploader::new(drive.config()).unwrap().upload_file(&dir.join("small.txt"), "parent-id").unwrap();
+    assert_eq!(file.id, "file-1");
+    assert_eq!(file.md5.as_deref(), Some(md5_hex(data).as_str()));
+    drive.verify();
+}
+
+#[test]
+fn upload_file_asks_for_all_drives_only_with_a_shared_drive() {
+    let drive = MockDrive::start();
+    drive.token("tok");
+    let data = b"contents";
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/upload/drive/v3/files"))
+            .and(query_param("supportsAllDrives", "true"))
+            .respond_with(uploaded("shared-1", data))
+            .expect(1),
+    );
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/upload/drive/v3/files"))
+            .and(query_param_is_missing("supportsAllDrives"))
+            .respond_with(uploaded("mine-1", data))
+            .expect(1),
+    );
+
+    let dir = scratch("upload-file-drives");
+    fs::write(dir.join("a.txt"), data).unwrap();
+    let mine = Uploader::new(drive.config()).unwrap().upload_file(&dir.join("a.txt"), "parent-id").unwrap();
+    let shared = Uploader::new(drive.config().shared_drive("drive-1")).unwrap();
+    let shared = shared.upload_file(&dir.join("a.txt"), "folder-id").unwrap();
+    assert_eq!((mine.id.as_str(), mine.size), ("mine-1", Some(8)));
+    assert_eq!(shared.id, "shared-1");
+    drive.verify();
+}
+
+#[test]
+fn uploads_a_large_file_through_a_resumable_session() {
+    let drive = MockDrive::start();
+    drive.token("tok");
+    let data: Vec<u8> = (0..6_000_000u32).map(|i| (i % 251) as u8).collect();
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/upload/drive/v3/files"))
+            .and(query_param("uploadType", "resumable"))
+            .and(header("x-upload-content-length", "6000000"))
+            .respond_with(
+                ResponseTemplate::new(200).insert_header("Location", format!("{}/session/1", drive.server.uri())),
+            )
+            .expect(1),
+    );
+    drive.mount(
+        Mock::given(method("PUT"))
+            .and(path("/session/1"))
+            .and(header("content-range", "bytes 0-5999999/6000000"))
+            .respond_with(uploaded("big-1", &data))
+            .expect(1),
+    );
+
+    let dir = scratch("resumable");
+    fs::write(dir.join("big.bin"), &data).unwrap();
+    let file = Uploader::new(drive.config()).unwrap().upload_file(&dir.join("big.bin"), "parent-id").unwrap();
+    assert_eq!(file.id, "big-1");
+    drive.verify();
+}
+
+#[test]
+fn a_compressed_upload_saves_no_session() {
+    let drive = MockDrive::start();
+    drive.token("tok");
+    drive.root_folder_with(json!([]));
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/upload/drive/v3/files"))
+            .and(query_param("uploadType", "resumable"))
+            .respond_with(
+                ResponseTemplate::new(200).insert_header("Location", format!("{}/session/1", drive.server.uri())),
+            )
+            .expect(1),
+    );
+    // Part way through, if it gets this far, when the deadline gives up on it.
+    drive.mount(
+        Mock::given(method("PUT"))
+            .and(path("/session/1"))
+            .respond_with(
+                ResponseTemplate::new(308)
+                    .insert_header("Range", "bytes=0-262143")
+                    .set_delay(Duration::from_millis(1500)),
+            ),
+    );
+
+    let dir = scratch("resumable-gz");
+    let source = dir.join("source");
+    fs::create_dir(&source).unwrap();
+    // Noise, so the gzip copy still takes the resumable path.
+    let mut x = 0x2545_f491_u32;
+    let data: Vec<u8> = (0..6_000_000)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            x as u8
+        })
+        .collect();
+    fs::write(source.join("big.bin"), &data).unwrap();
+    let state = dir.join("upload-state.json");
+    let args = ["--compress", "--state", state.to_str().unwrap(), "--deadline", "1s", "--deadline-abandon"];
+    let summary = Uploader::new(drive.config_from(&args)).unwrap().upload_dir(&source).unwrap();
+    assert!(summary.deadline_reached);
+    // The temp copy it was for is gone, so resuming it could never match.
+    assert!(!dir.join("upload-state.sessions.json").exists());
+    drive.verify();
+}
+
+#[test]
+fn a_401_refreshes_the_token_and_sends_again() {
+    let drive = MockDrive::start();
+    // The first token is the one construction fetches; it has expired by the upload.
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "access_token": "expired" })))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .expect(1),
+    );
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "access_token": "fresh" })))
+            .expect(1),
+    );
+    let data = b"contents";
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/upload/drive/v3/files"))
+            .and(header("authorization", "Bearer expired"))
+            .respond_with(ResponseTemplate::new(401))
+            .expect(1),
+    );
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/upload/drive/v3/files"))
+            .and(header("authorization", "Bearer fresh"))
+            .respond_with(uploaded("file-1", data))
+            .expect(1),
+    );
+
+    let dir = scratch("refresh");
+    fs::write(dir.join("a.txt"), data).unwrap();
+    let file = Uploader::new(drive.config()).unwrap().upload_file(&dir.join("a.txt"), "parent-id").unwrap();
+    assert_eq!(file.id, "file-1");
+    drive.verify();
+}
+
+#[test]
+fn a_5xx_is_retried_with_backoff() {
+    let drive = MockDrive::start();
+    drive.token("tok");
+    drive.empty_folders();
+    let data = b"contents";
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/upload/drive/v3/files"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(2)
+            .with_priority(1)
+            .expect(2),
+    );
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/upload/drive/v3/files"))
+            .respond_with(uploaded("file-1", data))
+            .expect(1),
+    );
+
+    let dir = scratch("retry");
+    fs::write(dir.join("a.txt"), data).unwrap();
+    let file = Uploader::new(drive.config().max_retries(3)).unwrap().upload_file(&dir.join("a.txt"), "parent-id").unwrap();
+    assert_eq!(file.id, "file-1");
+    drive.verify();
+}
+
+#[test]
+fn a_5xx_past_the_retries_fails_the_file() {
+    let drive = MockDrive::start();
+    drive.token("tok");
+    drive.empty_folders();
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/upload/drive/v3/files"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(3),
+    );
+
+    let dir = scratch("retry-exhausted");
+    fs::write(dir.join("a.txt"), b"contents").unwrap();
+    let result = Uploader::new(drive.config().max_retries(2)).unwrap().upload_file(&dir.join("a.txt"), "parent-id");
+    assert!(result.is_err());
+    drive.verify();
+}
+
+#[test]
+fn a_create_that_failed_midway_is_found_instead_of_sent_again() {
+    let drive = MockDrive::start();
+    drive.token("tok");
+    let data = b"contents";
+    // Drive made the file but the response was lost.
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/upload/drive/v3/files"))
+            .respond_with(ResponseTemplate::new(502))
+            .expect(1),
+    );
+    drive.mount(
+        Mock::given(method("GET"))
+            .and(path("/drive/v3/files"))
+            .and(query_param_contains("q", "create_id"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "files": [{ "id": "file-1", "md5Checksum": md5_hex(data) }],
+            })))
+            .expect(1),
+    );
+
+    let dir = scratch("create-found");
+    fs::write(dir.join("a.txt"), data).unwrap();
+    let file = Uploader::new(drive.config().max_retries(3)).unwrap().upload_file(&dir.join("a.txt"), "parent-id").unwrap();
+    assert_eq!(file.id, "file-1");
+    drive.verify();
+}
+
+#[test]
+fn a_replacement_is_sent_again_without_a_search() {
+    let drive = MockDrive::start();
+    drive.token("tok");
+    let data = b"contents";
+    drive.mount(
+        Mock::given(method("PATCH"))
+            .and(path("/upload/drive/v3/files/old-id"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .expect(1),
+    );
+    drive.mount(
+        Mock::given(method("PATCH"))
+            .and(path("/upload/drive/v3/files/old-id"))
+            .respond_with(uploaded("old-id", data))
+            .expect(1),
+    );
+    drive.mount(
+        Mock::given(method("GET"))
+            .and(path("/drive/v3/files"))
+            .and(query_param_contains("q", "'root-id' in parents"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "files": [{ "id": "old-id", "name": "a.txt", "size": "3" }],
+            })))
+            .with_priority(1),
+    );
+    drive.empty_folders();
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/drive/v3/files"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "id": "root-id" }))),
+    );
+
+    let dir = scratch("replace-retry");
+    fs::write(dir.join("a.txt"), data).unwrap();
+    let uploader = Uploader::new(drive.config().on_conflict(OnConflict::Replace).max_retries(3)).unwrap();
+    let summary = uploader.upload_dir(&dir).unwrap();
+    assert_eq!((summary.uploaded, summary.failed), (1, 0));
+    drive.verify();
+}
+
+/// Mounts catch-alls for uploads and trashing that `verify` fails on if hit.
+fn expect_no_changes(drive: &MockDrive) {
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/upload/drive/v3/files"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(0),
+    );
+    drive.mount(
+        Mock::given(method("PATCH"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(0),
+    );
+}
+
+#[test]
+fn a_compressed_upload_is_found_under_its_gz_name() {
+    let drive = MockDrive::start();
+    drive.token("tok");
+    // What the last run left: a.txt gzipped, tagged as this tool's upload.
+    drive.root_folder_with(json!([{ "id": "gz-id", "name": "a.txt.gz", "size": "40" }]));
+    expect_no_changes(&drive);
+
+    let dir = scratch("mirror-gz");
+    fs::write(dir.join("a.txt"), b"contents").unwrap();
+    let config = drive.config_from(&["--compress", "--mirror", "--on-conflict", "skip"]);
+    let summary = Uploader::new(config).unwrap().upload_dir(&dir).unwrap();
+    assert_eq!((summary.uploaded, summary.skipped), (0, 1));
+    drive.verify();
+}
+
+#[test]
+fn an_encrypted_upload_is_found_under_its_enc_name() {
+    let drive = MockDrive::start();
+    drive.token("tok");
+    drive.root_folder_with(json!([{ "id": "enc-id", "name": "a.txt.gz.enc", "size": "90" }]));
+    expect_no_changes(&drive);
+
+    let dir = scratch("mirror-enc");
+    let source = dir.join("source");
+    fs::create_dir(&source).unwrap();
+    fs::write(source.join("a.txt"), b"contents").unwrap();
+    let key_file = dir.join("key");
+    fs::write(&key_file, "passphrase\n").unwrap();
+    let args = ["--compress", "--encrypt", "--key-file", key_file.to_str().unwrap(), "--mirror", "--on-conflict", "skip"];
+    let summary = Uploader::new(drive.config_from(&args)).unwrap().upload_dir(&source).unwrap();
+    assert_eq!((summary.uploaded, summary.skipped), (0, 1));
+    drive.verify();
+}
+
+#[test]
+fn every_queued_upload_finishes_before_the_run_returns() {
+    let drive = MockDrive::start();
+    drive.token("tok");
+    drive.empty_folders();
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/drive/v3/files"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "id": "root-id" }))),
+    );
+    // Two workers at a second per file: over five seconds of uploads.
+    let data = b"slow";
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/upload/drive/v3/files"))
+            .respond_with(uploaded("file", data).set_delay(Duration::from_secs(1)))
+            .expect(12),
+    );
+
+    let dir = scratch("slow");
+    for i in 0..12 {
+        fs::write(dir.join(format!("{}.txt", i)), data).unwrap();
+    }
+    let started = std::time::Instant::now();
+    let summary = Uploader::new(drive.config().threads(2)).unwrap().upload_dir(&dir).unwrap();
+    assert!(started.elapsed() >= Duration::from_secs(5));
+    assert_eq!((summary.files, summary.uploaded, summary.failed, summary.cancelled), (12, 12, 0, 0));
+    drive.verify();
+}
+
+#[test]
+fn uploads_hitting_401_together_share_one_refresh() {
+    let drive = MockDrive::start();
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "access_token": "expired" })))
+            .up_to_n_times(1)
+            .with_priority(1),
+    );
+    // Slow enough that the other uploads get their 401 while it is running.
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(json!({ "access_token": "fresh" }))
+                    .set_delay(Duration::from_millis(300)),
+            )
+            .expect(1),
+    );
+    let data = b"same";
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/upload/drive/v3/files"))
+            .and(header("authorization", "Bearer expired"))
+            .respond_with(ResponseTemplate::new(401)),
+    );
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/upload/drive/v3/files"))
+            .and(header("authorization", "Bearer fresh"))
+            .respond_with(uploaded("file", data))
+            .expect(8),
+    );
+
+    let dir = scratch("storm");
+    let uploader = Uploader::new(drive.config()).unwrap();
+    std::thread::scope(|scope| {
+        for i in 0..8 {
+            let file = dir.join(format!("{}.txt", i));
+            fs::write(&file, data).unwrap();
+            let uploader = &uploader;
+            scope.spawn(move || uploader.upload_file(&file, "parent-id").unwrap());
+        }
+    });
+    drive.verify();
+}
+
+#[test]
+fn a_slow_refresh_does_not_hold_up_other_uploads() {
+    let drive = MockDrive::start();
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "access_token": "tok" })))
+            .up_to_n_times(1)
+            .with_priority(1),
+    );
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(json!({ "access_token": "tok" }))
+                    .set_delay(Duration::from_secs(3)),
+            )
+            .expect(1),
+    );
+    // One upload is refused once, the token stays good for the others.
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/upload/drive/v3/files"))
+            .and(body_string_contains(r#""name":"refused.txt""#))
+            .respond_with(ResponseTemplate::new(401))
+            .up_to_n_times(1)
+            .with_priority(1),
+    );
+    let data = b"data";
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/upload/drive/v3/files"))
+            .respond_with(uploaded("file", data))
+            .expect(2),
+    );
+
+    let dir = scratch("slow-refresh");
+    fs::write(dir.join("refused.txt"), data).unwrap();
+    fs::write(dir.join("other.txt"), data).unwrap();
+    let uploader = Uploader::new(drive.config()).unwrap();
+    std::thread::scope(|scope| {
+        let refused = scope.spawn(|| uploader.upload_file(&dir.join("refused.txt"), "parent-id"));
+        std::thread::sleep(Duration::from_millis(500));
+        let started = std::time::Instant::now();
+        uploader.upload_file(&dir.join("other.txt"), "parent-id").unwrap();
+        assert!(started.elapsed() < Duration::from_secs(2), "waited {:?} on the refresh", started.elapsed());
+        refused.join().unwrap().unwrap();
+    });
+    drive.verify();
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn the_async_pool_refreshes_and_retries_like_the_threads() {
+    let drive = MockDrive::start();
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "access_token": "expired" })))
+            .up_to_n_times(1)
+            .with_priority(1),
+    );
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "access_token": "fresh" })))
+            .expect(1),
+    );
+    drive.empty_folders();
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/drive/v3/files"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "id": "root-id" }))),
+    );
+    let data = b"contents";
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/upload/drive/v3/files"))
+            .and(header("authorization", "Bearer expired"))
+            .respond_with(ResponseTemplate::new(401))
+            .expect(1),
+    );
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/upload/drive/v3/files"))
+            .and(header("authorization", "Bearer fresh"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .expect(1),
+    );
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/upload/drive/v3/files"))
+            .and(header("authorization", "Bearer fresh"))
+            .respond_with(uploaded("file-1", data))
+            .expect(1),
+    );
+
+    let dir = scratch("async");
+    fs::write(dir.join("a.txt"), data).unwrap();
+    let summary = Uploader::new(drive.config().async_io(true)).unwrap().upload_dir(&dir).unwrap();
+    assert_eq!((summary.files, summary.uploaded, summary.failed), (1, 1, 0));
+    drive.verify();
+}
+
+/// Writes a tar of `files`, each a path inside it and its contents.
+fn tar_of(archive: &Path, files: &[(&str, &[u8])]) {
+    let mut tar = tar::Builder::new(fs::File::create(archive).unwrap());
+    for (name, data) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(1_700_000_000);
+        tar.append_data(&mut header, name, *data).unwrap();
+    }
+    tar.finish().unwrap();
+}
+
+#[test]
+fn an_archive_is_filtered_like_a_folder() {
+    let drive = MockDrive::start();
+    drive.token("tok");
+    // As big as same.txt, so --on-conflict duplicate takes it for the same file.
+    drive.root_folder_with(json!([{ "id": "same-id", "name": "same.txt", "size": "4" }]));
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/upload/drive/v3/files"))
+            .and(body_string_contains("\"name\":\"keep.txt\""))
+            .respond_with(uploaded("keep-id", b"keep"))
+            .expect(1),
+    );
+    expect_no_changes(&drive);
+
+    let dir = scratch("archive-filters");
+    let archive = dir.join("a.tar");
+    tar_of(&archive, &[
+        ("keep.txt", b"keep"),
+        ("same.txt", b"same"),
+        ("debug.log", b"log"),
+        (".git/config", b"hidden"),
+    ]);
+    let args = ["--exclude", "*.log", "--on-conflict", "duplicate"];
+    let summary = Uploader::new(drive.config_from(&args)).unwrap().upload_archive(&archive).unwrap();
+    assert_eq!((summary.uploaded, summary.skipped, summary.hidden), (1, 1, 1));
+    drive.verify();
+}
+
+#[test]
+fn a_damaged_streamed_entry_is_deleted() {
+    let drive = MockDrive::start();
+    drive.token("tok");
+    drive.root_folder_with(json!([]));
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/upload/drive/v3/files"))
+            .and(query_param("uploadType", "resumable"))
+            .respond_with(
+                ResponseTemplate::new(200).insert_header("Location", format!("{}/session/1", drive.server.uri())),
+            )
+            .expect(1),
+    );
+    drive.mount(
+        Mock::given(method("PUT"))
+            .and(path("/session/1"))
+            .respond_with(uploaded("bad-id", b"something else"))
+            .expect(1),
+    );
+    drive.mount(
+        Mock::given(method("DELETE"))
+            .and(path("/drive/v3/files/bad-id"))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1),
+    );
+
+    let dir = scratch("archive-damaged");
+    let archive = dir.join("a.tar");
+    let data: Vec<u8> = (0..6_000_000u32).map(|i| (i % 251) as u8).collect();
+    tar_of(&archive, &[("big.bin", &data)]);
+    let summary = Uploader::new(drive.config()).unwrap().upload_archive(&archive).unwrap();
+    assert_eq!((summary.uploaded, summary.failed), (0, 1));
+    drive.verify();
+}
+
+#[test]
+fn an_upload_without_a_checksum_keeps_the_local_file() {
+    let drive = MockDrive::start();
+    drive.token("tok");
+    drive.root_folder_with(json!([]));
+    // As for a file converted to a Google Docs format.
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/upload/drive/v3/files"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "id": "doc-1" })))
+            .expect(1),
+    );
+
+    let dir = scratch("after-upload-unchecked");
+    fs::write(dir.join("a.txt"), b"contents").unwrap();
+    let summary = Uploader::new(drive.config_from(&["--after-upload", "delete"])).unwrap().upload_dir(&dir).unwrap();
+    assert_eq!((summary.uploaded, summary.removed_local, summary.kept_local), (1, 0, 1));
+    assert!(dir.join("a.txt").exists());
+    drive.verify();
+}
+
+#[test]
+fn after_upload_needs_verification() {
+    let args = ["drive-uploader", "--after-upload", "delete", "--no-verify"];
+    assert!(Cli::try_parse_from(args).is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn a_symlink_loop_is_walked_once() {
+    let drive = MockDrive::start();
+    let dir = scratch("symlink-loop");
+    fs::write(dir.join("a.txt"), b"contents").unwrap();
+    std::os::unix::fs::symlink(".", dir.join("loop")).unwrap();
+
+    // Skipped as a link by default.
+    let summary = Uploader::new(drive.config_from(&["--dry-run"])).unwrap().upload_dir(&dir).unwrap();
+    assert_eq!((summary.files, summary.skipped), (1, 1));
+
+    // Followed, it leads back to the source, which has been walked already.
+    let args = ["--dry-run", "--follow-symlinks"];
+    let summary = Uploader::new(drive.config_from(&args)).unwrap().upload_dir(&dir).unwrap();
+    assert_eq!((summary.files, summary.skipped), (1, 1));
+}
+
+#[test]
+fn an_empty_file_is_created_from_metadata_alone() {
+    let drive = MockDrive::start();
+    drive.token("tok");
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/drive/v3/files"))
+            .and(body_partial_json(json!({ "name": "empty.txt", "parents": ["parent-id"] })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "id": "empty-1" })))
+            .expect(1),
+    );
+    drive.mount(
+        Mock::given(path("/upload/drive/v3/files"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(0),
+    );
+    drive.mount(Mock::given(method("PUT")).respond_with(ResponseTemplate::new(500)).expect(0));
+
+    let dir = scratch("empty-file");
+    fs::write(dir.join("empty.txt"), b"").unwrap();
+    let file = Uploader::new(drive.config()).unwrap().upload_file(&dir.join("empty.txt"), "parent-id").unwrap();
+    assert_eq!((file.id.as_str(), file.size), ("empty-1", Some(0)));
+    drive.verify();
+}
+
+/// Reads one HTTP/1.1 request off `stream`, returning its request line.
+/// Bodies come with a Content-Length or chunked.
+fn read_request(stream: &mut TcpStream) -> String {
+    let mut data = Vec::new();
+    let mut buf = [0u8; 8192];
+    let head_end = loop {
+        if let Some(at) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            break at + 4;
+        }
+        let n = stream.read(&mut buf).unwrap();
+        assert!(n > 0, "connection closed mid-request");
+        data.extend_from_slice(&buf[..n]);
+    };
+    let head = String::from_utf8_lossy(&data[..head_end]).to_lowercase();
+    let length = head
+        .lines()
+        .find_map(|line| line.strip_prefix("content-length:"))
+        .map(|n| n.trim().parse::<usize>().unwrap());
+    loop {
+        let body = &data[head_end..];
+        let done = match length {
+            Some(length) => body.len() >= length,
+            None if head.contains("transfer-encoding: chunked") => body.ends_with(b"0\r\n\r\n"),
+            None => true,
+        };
+        if done {
+            break;
+        }
+        let n = stream.read(&mut buf).unwrap();
+        assert!(n > 0, "connection closed mid-body");
+        data.extend_from_slice(&buf[..n]);
+    }
+    head.lines().next().unwrap_or_default().to_string()
+}
+
+#[test]
+fn an_upload_whose_connection_drops_is_sent_again() {
+    // wiremock can't drop a connection, so this server is written by hand.
+    // It closes every connection after one response, and the first upload's
+    // without any.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base = format!("http://{}", listener.local_addr().unwrap());
+    let data = b"contents";
+    let md5 = md5_hex(data);
+    let server = thread::spawn(move || {
+        let mut uploads = 0;
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let request = read_request(&mut stream);
+            let body = if request.starts_with("post /token") {
+                json!({ "access_token": "tok", "expires_in": 3600 })
+            } else if request.starts_with("get /drive/v3/files") {
+                // The search for a create that may have gone through.
+                json!({ "files": [] })
+            } else if request.starts_with("post /upload/drive/v3/files") {
+                uploads += 1;
+                if uploads == 1 {
+                    continue;
+                }
+                json!({ "id": "a-1", "md5Checksum": md5 })
+            } else {
+                panic!("unexpected request {}", request);
+            };
+            let body = body.to_string();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            stream.write_all(response.as_bytes()).unwrap();
+            if uploads == 2 {
+                return uploads;
+            }
+        }
+        uploads
+    });
+
+    let dir = scratch("dropped-connection");
+    fs::write(dir.join("a.txt"), data).unwrap();
+    let oauth = OAuthConfig::from_refresh_token("client-id", "client-secret", "refresh-token");
+    let config = UploaderConfig::from(Cli::parse_from(["drive-uploader"]))
+        .oauth(oauth)
+        .endpoints(DriveEndpoints::at(&base))
+        .requests_per_100s(0)
+        .retry_delay(Duration::from_millis(10))
+        .max_retries(3)
+        .quiet(true);
+    let file = Uploader::new(config).unwrap().upload_file(&dir.join("a.txt"), "parent-id").unwrap();
+    assert_eq!(file.id, "a-1");
+    assert_eq!(server.join().unwrap(), 2);
+}
+
+#[test]
+fn a_token_expiring_between_folder_creates_refreshes_once() {
+    let drive = MockDrive::start();
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "access_token": "expired" })))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .expect(1),
+    );
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "access_token": "fresh" })))
+            .expect(1),
+    );
+    drive.empty_folders();
+    // Still good for the root; it expires before the folder below.
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/drive/v3/files"))
+            .and(body_partial_json(json!({ "name": "ImportantFiles", "mimeType": FOLDER_MIME })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "id": "root-id" })))
+            .expect(1),
+    );
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/drive/v3/files"))
+            .and(body_partial_json(json!({ "name": "sub", "mimeType": FOLDER_MIME })))
+            .and(header("authorization", "Bearer expired"))
+            .respond_with(ResponseTemplate::new(401))
+            .expect(1),
+    );
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/drive/v3/files"))
+            .and(body_partial_json(json!({ "name": "sub", "mimeType": FOLDER_MIME, "parents": ["root-id"] })))
+            .and(header("authorization", "Bearer fresh"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "id": "sub-id" })))
+            .expect(1),
+    );
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/upload/drive/v3/files"))
+            .and(body_string_contains(r#""parents":["sub-id"]"#))
+            .respond_with(uploaded("file-b", b"two"))
+            .expect(1),
+    );
+
+    let dir = scratch("folder-refresh");
+    fs::create_dir(dir.join("sub")).unwrap();
+    fs::write(dir.join("sub/b.txt"), b"two").unwrap();
+    let summary = Uploader::new(drive.config()).unwrap().upload_dir(&dir).unwrap();
+    assert_eq!((summary.uploaded, summary.failed, summary.failed_folders), (1, 0, 0));
+    drive.verify();
+}
+
+#[cfg(unix)]
+#[test]
+fn a_file_removed_before_its_upload_is_counted_as_vanished() {
+    let drive = MockDrive::start();
+    drive.token("tok");
+    drive.root_folder_with(json!([]));
+    expect_no_changes(&drive);
+
+    let dir = scratch("vanished");
+    let source = dir.join("source");
+    fs::create_dir(&source).unwrap();
+    fs::write(source.join("temp.txt"), b"contents").unwrap();
+    // The hook runs once the file is queued and stat'd, just before it is read.
+    let summary = Uploader::new(drive.config_from(&["--pre-upload-cmd", "rm {path}"]))
+        .unwrap()
+        .upload_dir(&source)
+        .unwrap();
+    assert_eq!((summary.uploaded, summary.failed, summary.vanished), (0, 0, 1));
+    drive.verify();
+}
+
+#[test]
+fn a_folder_that_cannot_be_listed_is_skipped_not_uploaded_again() {
+    let drive = MockDrive::start();
+    drive.token("tok");
+    drive.mount(
+        Mock::given(method("GET"))
+            .and(path("/drive/v3/files"))
+            .and(query_param_contains("q", "'root' in parents"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "files": [{ "id": "root-id" }] })))
+            .with_priority(1),
+    );
+    drive.mount(
+        Mock::given(method("GET"))
+            .and(path("/drive/v3/files"))
+            .and(query_param_contains("q", "'root-id' in parents"))
+            .respond_with(ResponseTemplate::new(500))
+            .with_priority(1),
+    );
+    drive.empty_folders();
+    expect_no_changes(&drive);
+
+    let dir = scratch("unlisted-folder");
+    fs::write(dir.join("a.txt"), b"contents").unwrap();
+    let summary = Uploader::new(drive.config().max_retries(1)).unwrap().upload_dir(&dir).unwrap();
+    assert_eq!((summary.uploaded, summary.failed_folders), (0, 1));
+    drive.verify();
+}
+
+/// Has the root folder's trash hold `files`. Mounted before
+/// root_folder_with, so it answers the trash's listing first.
+fn trash_with(drive: &MockDrive, files: serde_json::Value) {
+    drive.mount(
+        Mock::given(method("GET"))
+            .and(path("/drive/v3/files"))
+            .and(query_param_contains("q", "'root-id' in parents and trashed=true"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "files": files })))
+            .with_priority(1)
+            .expect(1),
+    );
+}
+
+#[test]
+fn a_trashed_copy_of_the_same_file_is_restored() {
+    let drive = MockDrive::start();
+    drive.token("tok");
+    let data = b"contents";
+    trash_with(&drive, json!([{ "id": "old-id", "name": "a.txt", "size": "8", "md5Checksum": md5_hex(data) }]));
+    drive.root_folder_with(json!([]));
+    drive.mount(
+        Mock::given(method("PATCH"))
+            .and(path("/drive/v3/files/old-id"))
+            .and(body_partial_json(json!({ "trashed": false })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "id": "old-id" })))
+            .expect(1),
+    );
+    expect_no_changes(&drive);
+
+    let dir = scratch("untrash-match");
+    fs::write(dir.join("a.txt"), data).unwrap();
+    let summary = Uploader::new(drive.config_from(&["--untrash"])).unwrap().upload_dir(&dir).unwrap();
+    assert_eq!((summary.uploaded, summary.restored), (0, 1));
+    drive.verify();
+}
+
+#[test]
+fn a_trashed_file_with_other_contents_is_uploaded_again() {
+    let drive = MockDrive::start();
+    drive.token("tok");
+    let data = b"contents";
+    trash_with(&drive, json!([{ "id": "old-id", "name": "a.txt", "size": "8", "md5Checksum": md5_hex(b"CONTENTS") }]));
+    drive.root_folder_with(json!([]));
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/upload/drive/v3/files"))
+            .respond_with(uploaded("new-id", data))
+            .expect(1),
+    );
+    drive.mount(Mock::given(method("PATCH")).respond_with(ResponseTemplate::new(500)).expect(0));
+
+    let dir = scratch("untrash-differs");
+    fs::write(dir.join("a.txt"), data).unwrap();
+    let summary = Uploader::new(drive.config_from(&["--untrash"])).unwrap().upload_dir(&dir).unwrap();
+    assert_eq!((summary.uploaded, summary.restored), (1, 0));
+    drive.verify();
+}
+
+/// Has --parent `parent-id`, which the run uploads straight into, be an
+/// empty folder in a shared drive.
+fn parent_in_shared_drive(drive: &MockDrive) {
+    drive.mount(
+        Mock::given(method("GET"))
+            .and(path("/drive/v3/files/parent-id"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "id": "parent-id",
+                "mimeType": FOLDER_MIME,
+                "trashed": false,
+                "driveId": "shared-1",
+            }))),
+    );
+    drive.empty_folders();
+}
+
+#[test]
+fn the_root_is_shared_with_shared_drive_flags_under_a_shared_parent() {
+    let drive = MockDrive::start();
+    drive.token("tok");
+    parent_in_shared_drive(&drive);
+    let data = b"contents";
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/upload/drive/v3/files"))
+            .respond_with(uploaded("file-1", data))
+            .expect(1),
+    );
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/drive/v3/files/parent-id/permissions"))
+            .and(query_param("supportsAllDrives", "true"))
+            .and(body_partial_json(json!({ "emailAddress": "pat@example.com", "role": "reader" })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "id": "perm-1" })))
+            .expect(1),
+    );
+
+    let dir = scratch("share-root-shared-drive");
+    fs::write(dir.join("a.txt"), data).unwrap();
+    let args = ["--parent", "parent-id", "--share-with", "pat@example.com:reader"];
+    let summary = Uploader::new(drive.config_from(&args)).unwrap().upload_dir(&dir).unwrap();
+    assert_eq!((summary.uploaded, summary.share_failed), (1, 0));
+    drive.verify();
+}
+
+#[test]
+fn each_file_is_shared_with_shared_drive_flags_under_a_shared_parent() {
+    let drive = MockDrive::start();
+    drive.token("tok");
+    parent_in_shared_drive(&drive);
+    let data = b"contents";
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/upload/drive/v3/files"))
+            .respond_with(uploaded("file-1", data))
+            .expect(1),
+    );
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/drive/v3/files/file-1/permissions"))
+            .and(query_param("supportsAllDrives", "true"))
+            .and(query_param("sendNotificationEmail", "false"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "id": "perm-1" })))
+            .expect(1),
+    );
+    drive.mount(
+        Mock::given(method("POST"))
+            .and(path("/drive/v3/files/parent-id/permissions"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(0),
+    );
+
+    let dir = scratch("share-files-shared-drive");
+    fs::write(dir.join("a.txt"), data).unwrap();
+    let args = ["--parent", "parent-id", "--share-with", "pat@example.com:reader", "--share-files"];
+    let summary = Uploader::new(drive.config_from(&args)).unwrap().upload_dir(&dir).unwrap();
+    assert_eq!((summary.uploaded, summary.share_failed), (1, 0));
+    drive.verify();
+}
+
+#[cfg(unix)]
+#[test]
+fn verify_reports_an_unreadable_file_and_goes_on() {
+    let drive = MockDrive::start();
+    drive.token("tok");
+    drive.root_folder_with(json!([{ "id": "a-id", "name": "a.txt", "size": "3", "md5Checksum": md5_hex(b"one") }]));
+
+    let dir = scratch("verify-unreadable");
+    // Sorted first, so the files after it are only checked if verify goes on.
+    std::os::unix::fs::symlink(dir.join("nowhere"), dir.join("0-dangling")).unwrap();
+    fs::write(dir.join("a.txt"), b"one").unwrap();
+    fs::write(dir.join("b.txt"), b"two").unwrap();
+    let uploader = Uploader::new(drive.config_from(&["--follow-symlinks"])).unwrap();
+    let err = uploader.verify_dir(&dir).expect_err("b.txt is missing");
+    assert_eq!(err.to_string(), "1 files are missing or different in Drive");
+}